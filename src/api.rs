@@ -1,11 +1,12 @@
 use std::collections::HashMap;
 
+pub use super::complex::Complex;
 pub use super::expression::Expression;
-pub use super::parser::{Parser, Token};
+pub use super::parser::{Parser, Token, Variable};
 
-pub fn eval(input: String, x: Option<f64>) -> Option<f64> {
-    let mut expr = Expression::new(input);
+pub fn eval(input: String, x: Option<f64>) -> Option<Complex> {
+    let expr = Expression::new(input);
     let mut variables = HashMap::new();
-    variables.insert("x".to_string(), x.unwrap_or(0.0));
-    expr.eval_with_var()
+    variables.insert(Variable::X, Complex::real(x.unwrap_or(0.0)));
+    expr.eval_with_var(&variables)
 }
@@ -0,0 +1,230 @@
+// The evaluator's numeric backend. A plain f64 goes NaN the moment an
+// expression like `sqrt(-1)` or `(-8)^(1/3)` produces a non-real
+// intermediate value, so every `Number` in the parser is actually one of
+// these instead.
+
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Self {
+        Self { re, im }
+    }
+
+    // A real number is just a complex number with no imaginary part.
+    pub fn real(re: f64) -> Self {
+        Self { re, im: 0.0 }
+    }
+
+    pub fn is_real(&self) -> bool {
+        self.im == 0.0
+    }
+
+    pub fn abs(self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    pub fn arg(self) -> f64 {
+        // `-0.0` and `0.0` compare equal but atan2 treats them as opposite
+        // sides of the branch cut (atan2(-0.0, -1.0) == -pi, atan2(0.0,
+        // -1.0) == pi), so a real number whose `im` picked up a sign via
+        // `Neg` would otherwise land on the wrong principal branch.
+        let im = if self.im == 0.0 { 0.0 } else { self.im };
+        im.atan2(self.re)
+    }
+
+    pub fn ln(self) -> Self {
+        Complex::new(self.abs().ln(), self.arg())
+    }
+
+    pub fn exp(self) -> Self {
+        let r = self.re.exp();
+        Complex::new(r * self.im.cos(), r * self.im.sin())
+    }
+
+    // z^w via polar form: z^w = exp(w * ln(z)). Routing a plain real
+    // exponentiation through that identity loses precision (e.g. 9^0.5
+    // comes back as 3.0000000000000004), so fall back to `f64::powf`
+    // whenever both operands are real and the result is real too: that's
+    // always true for a non-negative base, and also true for a negative
+    // base raised to an integer exponent (e.g. (-2)^2), which `f64::powf`
+    // handles exactly but the log/exp path only approximates, leaving
+    // behind a spurious imaginary part made of floating-point noise.
+    pub fn powf(self, other: Self) -> Self {
+        if self.re == 0.0 && self.im == 0.0 {
+            return Complex::real(0.0);
+        }
+        if self.is_real() && other.is_real() && (self.re >= 0.0 || other.re.fract() == 0.0) {
+            return Complex::real(self.re.powf(other.re));
+        }
+        (other * self.ln()).exp()
+    }
+
+    pub fn sqrt(self) -> Self {
+        self.powf(Complex::real(0.5))
+    }
+
+    pub fn sin(self) -> Self {
+        Complex::new(
+            self.re.sin() * self.im.cosh(),
+            self.re.cos() * self.im.sinh(),
+        )
+    }
+
+    pub fn cos(self) -> Self {
+        Complex::new(
+            self.re.cos() * self.im.cosh(),
+            -self.re.sin() * self.im.sinh(),
+        )
+    }
+
+    pub fn tan(self) -> Self {
+        self.sin() / self.cos()
+    }
+
+    // Inverse trig via the standard logarithmic identities, e.g.
+    // asin(z) = -i * ln(iz + sqrt(1 - z^2)).
+    pub fn asin(self) -> Self {
+        let i = Complex::new(0.0, 1.0);
+        -i * (i * self + (Complex::real(1.0) - self * self).sqrt()).ln()
+    }
+
+    pub fn acos(self) -> Self {
+        let i = Complex::new(0.0, 1.0);
+        -i * (self + i * (Complex::real(1.0) - self * self).sqrt()).ln()
+    }
+
+    pub fn atan(self) -> Self {
+        let i = Complex::new(0.0, 1.0);
+        (i / Complex::real(2.0)) * ((Complex::real(1.0) - i * self).ln()
+            - (Complex::real(1.0) + i * self).ln())
+    }
+}
+
+impl From<f64> for Complex {
+    fn from(re: f64) -> Self {
+        Complex::real(re)
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, rhs: Self) -> Self {
+        Complex::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, rhs: Self) -> Self {
+        Complex::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, rhs: Self) -> Self {
+        Complex::new(
+            self.re * rhs.re - self.im * rhs.im,
+            self.re * rhs.im + self.im * rhs.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+    fn div(self, rhs: Self) -> Self {
+        let denom = rhs.re * rhs.re + rhs.im * rhs.im;
+        Complex::new(
+            (self.re * rhs.re + self.im * rhs.im) / denom,
+            (self.im * rhs.re - self.re * rhs.im) / denom,
+        )
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+    fn neg(self) -> Self {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+// Lets a purely real result compare straight against a literal, which is
+// most call sites in practice - `eval(...) == 10.0` reads better than
+// constructing a `Complex` just to check it.
+impl PartialEq<f64> for Complex {
+    fn eq(&self, other: &f64) -> bool {
+        self.im == 0.0 && self.re == *other
+    }
+}
+
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.im == 0.0 {
+            write!(f, "{}", self.re)
+        } else if self.re == 0.0 {
+            write!(f, "{}i", self.im)
+        } else if self.im < 0.0 {
+            write!(f, "{} - {}i", self.re, -self.im)
+        } else {
+            write!(f, "{} + {}i", self.re, self.im)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_complex {
+    use super::*;
+
+    #[test]
+    fn test_add() {
+        let a = Complex::new(1.0, 2.0);
+        let b = Complex::new(3.0, -1.0);
+        assert_eq!(a + b, Complex::new(4.0, 1.0));
+    }
+
+    #[test]
+    fn test_mul() {
+        // (2 + 3i)(1 - 1i) = 2 - 2i + 3i - 3i^2 = 5 + i
+        let a = Complex::new(2.0, 3.0);
+        let b = Complex::new(1.0, -1.0);
+        assert_eq!(a * b, Complex::new(5.0, 1.0));
+    }
+
+    #[test]
+    fn test_sqrt_of_negative_real() {
+        let result = Complex::real(-1.0).sqrt();
+        assert_eq!(result.re.round(), 0.0);
+        assert_eq!(result.im.round(), 1.0);
+    }
+
+    #[test]
+    fn test_real_compares_to_f64() {
+        assert_eq!(Complex::real(10.0), 10.0);
+    }
+
+    #[test]
+    fn test_display_real_only() {
+        assert_eq!(Complex::real(4.0).to_string(), "4");
+    }
+
+    #[test]
+    fn test_display_with_imaginary() {
+        assert_eq!(Complex::new(1.0, 2.0).to_string(), "1 + 2i");
+    }
+
+    #[test]
+    fn test_negative_base_with_integer_exponent_is_exactly_real() {
+        // The log/exp path alone picks up floating-point noise here, which
+        // would otherwise print as a spurious imaginary part.
+        let result = Complex::real(-2.0).powf(Complex::real(2.0));
+        assert_eq!(result, Complex::real(4.0));
+        assert_eq!(result.im, 0.0);
+    }
+}
@@ -0,0 +1,107 @@
+// Structured errors for the parser, replacing the ad-hoc `String` messages
+// it used to return. Every variant that can be tied to a spot in the
+// original input carries the byte offset of the offending character, so a
+// caller can render a caret pointing at it (see `EvalError::render`).
+
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EvalError {
+    // A character (or sequence) didn't match any token.
+    UnexpectedToken { pos: usize },
+    // A '(' or ')' with no matching partner.
+    MismatchedParen { pos: usize },
+    // `Token::Ident` with no matching entry in the definitions map.
+    UnknownFunction { name: String, pos: usize },
+    // An operator or function was evaluated with too few values on the
+    // stack beneath it.
+    MissingOperand { op: String, pos: usize },
+    // `Token::Variable` with no matching entry in the environment. There's
+    // no byte offset worth keeping here - `Token::Variable` doesn't carry
+    // one, since all three variable names are interchangeable single
+    // characters and the set of free variables is usually tiny.
+    UnboundVariable { name: String },
+    // A user-defined function recursed past `calculate_with_env`'s depth
+    // limit, e.g. `f(x) = f(x - 1)` with no base case. Caught here instead
+    // of letting it blow the native call stack and abort the process.
+    RecursionLimit { pos: usize },
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::UnexpectedToken { pos } => {
+                write!(f, "unexpected character at position {}", pos)
+            }
+            EvalError::MismatchedParen { pos } => {
+                write!(f, "mismatched parenthesis at position {}", pos)
+            }
+            EvalError::UnknownFunction { name, pos } => {
+                write!(f, "unknown function '{}' at position {}", name, pos)
+            }
+            EvalError::MissingOperand { op, pos } => {
+                write!(f, "missing operand for '{}' at position {}", op, pos)
+            }
+            EvalError::UnboundVariable { name } => {
+                write!(f, "unbound variable '{}'", name)
+            }
+            EvalError::RecursionLimit { pos } => {
+                write!(f, "recursion limit exceeded at position {}", pos)
+            }
+        }
+    }
+}
+
+impl EvalError {
+    // The byte offset this error points at, if any.
+    pub fn pos(&self) -> Option<usize> {
+        match self {
+            EvalError::UnexpectedToken { pos }
+            | EvalError::MismatchedParen { pos }
+            | EvalError::UnknownFunction { pos, .. }
+            | EvalError::MissingOperand { pos, .. }
+            | EvalError::RecursionLimit { pos } => Some(*pos),
+            EvalError::UnboundVariable { .. } => None,
+        }
+    }
+
+    // Renders the error as a two-line message with a caret under the
+    // offending character in `source`, e.g.:
+    //
+    //   mismatched parenthesis at position 4
+    //   1 + (2
+    //       ^
+    pub fn render(&self, source: &str) -> String {
+        match self.pos() {
+            Some(pos) => {
+                let caret = " ".repeat(pos) + "^";
+                format!("{}\n{}\n{}", self, source, caret)
+            }
+            None => self.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_eval_error {
+    use super::*;
+
+    #[test]
+    fn test_display_unexpected_token() {
+        let err = EvalError::UnexpectedToken { pos: 3 };
+        assert_eq!(err.to_string(), "unexpected character at position 3");
+    }
+
+    #[test]
+    fn test_render_points_caret_at_pos() {
+        let err = EvalError::MismatchedParen { pos: 4 };
+        assert_eq!(err.render("1 + (2"), "mismatched parenthesis at position 4\n1 + (2\n    ^");
+    }
+
+    #[test]
+    fn test_unbound_variable_has_no_position() {
+        let err = EvalError::UnboundVariable { name: "x".to_string() };
+        assert_eq!(err.pos(), None);
+        assert_eq!(err.render("x"), err.to_string());
+    }
+}
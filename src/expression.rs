@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
-use super::parser::{Parser, Token};
+use super::complex::Complex;
+use super::parser::{Definition, Parser, Token, Variable};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Stack {
@@ -26,6 +27,7 @@ impl Stack {
 pub struct Expression {
     input: String,
     pub stack: Stack,
+    definitions: HashMap<String, Definition>,
 }
 
 pub trait Sanitize {
@@ -54,13 +56,14 @@ impl Sanitize for String {
 impl Expression {
     pub fn new(input: String) -> Self {
         Self {
-            input: input,
+            input,
             stack: Stack::new(),
+            definitions: HashMap::new(),
         }
     }
 
     pub fn push_number(&mut self, num: f64) {
-        self.stack.push(Token::Number(num));
+        self.stack.push(Token::Number(Complex::real(num)));
     }
 
     pub fn tokens(&self) -> &Vec<Token> {
@@ -75,20 +78,41 @@ impl Expression {
         self.input.clone()
     }
 
-    pub fn eval_with_var(&self) -> Option<f64> {
+    // Swaps in a new expression to evaluate while keeping this Expression's
+    // user-defined functions around, so a REPL can reuse one instance.
+    pub fn set_input(&mut self, input: String) {
+        self.input = input;
+        self.stack = Stack::new();
+    }
+
+    pub fn is_definition(&self) -> bool {
+        Parser::new(self.input.clone()).try_definition().is_some()
+    }
+
+    // Parses `self.input` as `name(params) = body` and remembers it so
+    // later calls to `name(...)` can be evaluated.
+    pub fn define(&mut self) -> Result<(), String> {
+        let (name, params, body) = Parser::new(self.input.clone())
+            .try_definition()
+            .ok_or_else(|| "not a function definition".to_string())?;
+
+        let body_tokens = Parser::new(body)
+            .tokens()
+            .map_err(|e| e.to_string())?;
+        let body = Parser::shunting_yard(body_tokens).map_err(|e| e.to_string())?;
+
+        self.definitions.insert(name, Definition { params, body });
+        Ok(())
+    }
+
+    pub fn eval_with_var(&self, env: &HashMap<Variable, Complex>) -> Option<Complex> {
         // substitute variables
         let inp = self.input.clone();
 
         let binding = Parser::new(inp);
-        let tokens = binding.tokens();
-        let result = Parser::shunting_yard(tokens.unwrap().1);
-        let result = Parser::calculate(result.unwrap());
-
-        if let Ok(num) = result {
-            Some(num)
-        } else {
-            None
-        }
+        let tokens = binding.tokens().ok()?;
+        let result = Parser::shunting_yard(tokens).ok()?;
+        Parser::calculate_with_env(result, env, &self.definitions).ok()
     }
 }
 
@@ -96,29 +120,30 @@ impl Expression {
 
 mod test_eval_with_var {
     use super::*;
+    use crate::parser::Variable;
 
     #[test]
     fn five_x() {
         let expr = Expression::new("5x".to_string());
         let mut variables = HashMap::new();
-        variables.insert("x".to_string(), 2.0);
-        assert_eq!(expr.eval_with_var().unwrap(), 10.0);
+        variables.insert(Variable::X, Complex::real(2.0));
+        assert_eq!(expr.eval_with_var(&variables).unwrap(), 10.0);
     }
 
     #[test]
     fn five_sin_x() {
         let expr = Expression::new("5 * sin ( x )".to_string());
         let mut variables = HashMap::new();
-        variables.insert("x".to_string(), 2.0);
-        assert_eq!(expr.eval_with_var().unwrap().round(), 5.0);
+        variables.insert(Variable::X, Complex::real(2.0));
+        assert_eq!(expr.eval_with_var(&variables).unwrap().re.round(), 5.0);
     }
 
     #[test]
     fn not_a_math_expression() {
         let expr = Expression::new("not an exp".to_string());
         let mut variables = HashMap::new();
-        variables.insert("x".to_string(), 2.0);
-        let result = expr.eval_with_var();
+        variables.insert(Variable::X, Complex::real(2.0));
+        let result = expr.eval_with_var(&variables);
         assert_eq!(result, None);
     }
 
@@ -128,16 +153,35 @@ mod test_eval_with_var {
     fn complex_expression() {
         let expr = Expression::new("2 ( x + 1 ) / 2".to_string());
         let mut variables = HashMap::new();
-        variables.insert("x".to_string(), -1.0);
-        assert_eq!(expr.eval_with_var().unwrap(), 0.0);
+        variables.insert(Variable::X, Complex::real(-1.0));
+        assert_eq!(expr.eval_with_var(&variables).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn unbound_variable_returns_none() {
+        let expr = Expression::new("x + 1".to_string());
+        let variables = HashMap::new();
+        assert_eq!(expr.eval_with_var(&variables), None);
     }
 
     #[test]
     fn parse_5_x() {
-        let mut p = Parser::new("5x".to_string());
+        let p = Parser::new("5x".to_string());
         let result = p.tokens();
-        let result = Parser::shunting_yard(result.unwrap().1);
-        let result = Parser::calculate(result.unwrap());
+        let result = Parser::shunting_yard(result.unwrap());
+        let mut env = HashMap::new();
+        env.insert(Variable::X, Complex::real(1.0));
+        let result = Parser::calculate_with_env(result.unwrap(), &env, &HashMap::new());
         assert_eq!(result.unwrap(), 5.0);
     }
+
+    #[test]
+    fn user_defined_function() {
+        let mut expr = Expression::new("square(x)=x^2".to_string());
+        assert!(expr.is_definition());
+        expr.define().unwrap();
+
+        expr.set_input("square(3)".to_string());
+        assert_eq!(expr.eval_with_var(&HashMap::new()).unwrap(), 9.0);
+    }
 }
@@ -0,0 +1,92 @@
+// rustyline Helper that gives the REPL live syntax highlighting and
+// multi-line entry while parens are still unbalanced.
+
+use std::borrow::Cow;
+
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::Helper;
+
+use crate::parser::{Parser, Token};
+
+pub struct CalcHelper;
+
+impl Completer for CalcHelper {
+    type Candidate = String;
+}
+
+impl Hinter for CalcHelper {
+    type Hint = String;
+}
+
+impl Helper for CalcHelper {}
+
+impl Validator for CalcHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let parser = Parser::new(ctx.input().to_string());
+        let tokens = match parser.tokens() {
+            Ok(tokens) => tokens,
+            // Let `calculate` surface the real parse error once submitted.
+            Err(_) => return Ok(ValidationResult::Valid(None)),
+        };
+
+        let depth = tokens.iter().fold(0i32, |depth, token| match token {
+            Token::LeftParen(_) => depth + 1,
+            Token::RightParen(_) => depth - 1,
+            _ => depth,
+        });
+
+        if depth > 0 {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Highlighter for CalcHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        let parser = Parser::new(line.to_string());
+        let spans = match parser.tokens_with_spans() {
+            Ok(spans) => spans,
+            Err(_) => return Cow::Borrowed(line),
+        };
+
+        // Splice color codes around each token's original byte span instead
+        // of reconstructing the line from the token stream, so whitespace
+        // and layout stay exactly as the user typed them.
+        let mut colored = String::with_capacity(line.len());
+        let mut cursor = 0;
+
+        for (token, span) in spans {
+            let Some((start, end)) = span else {
+                continue;
+            };
+            colored.push_str(&line[cursor..start]);
+            colored.push_str(&colorize(&token, &line[start..end]));
+            cursor = end;
+        }
+        colored.push_str(&line[cursor..]);
+
+        Cow::Owned(colored)
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+fn colorize(token: &Token, text: &str) -> String {
+    match token {
+        Token::Number(_) => format!("\x1b[36m{}\x1b[0m", text),
+        Token::Variable(_) => format!("\x1b[32m{}\x1b[0m", text),
+        Token::Operator(_) => format!("\x1b[33m{}\x1b[0m", text),
+        Token::Function(_, _, _) | Token::Ident(_, _, _) => format!("\x1b[35m{}\x1b[0m", text),
+        Token::Negate | Token::Question(_) | Token::Colon => {
+            format!("\x1b[33m{}\x1b[0m", text)
+        }
+        Token::LeftParen(_) | Token::RightParen(_) | Token::Comma => text.to_string(),
+    }
+}
@@ -0,0 +1,6 @@
+pub mod api;
+pub mod complex;
+pub mod error;
+pub mod expression;
+pub mod helper;
+pub mod parser;
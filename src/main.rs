@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
+use pixela_eval::complex::Complex;
+use pixela_eval::expression::Expression;
+use pixela_eval::helper::CalcHelper;
+use pixela_eval::parser::Variable;
+
+fn parse_variable(name: &str) -> Option<Variable> {
+    match name {
+        "x" => Some(Variable::X),
+        "y" => Some(Variable::Y),
+        "z" => Some(Variable::Z),
+        _ => None,
+    }
+}
+
+// Like `line.split_once('=')`, but only for a standalone assignment '=' -
+// not one that's actually the second half of `==`/`!=`/`<=`/`>=`.
+fn split_assignment(line: &str) -> Option<(&str, &str)> {
+    let (name, rest) = line.split_once('=')?;
+    if rest.starts_with('=') || name.ends_with(['<', '>', '!']) {
+        return None;
+    }
+    Some((name, rest))
+}
+
+fn main() {
+    let mut rl = Editor::<CalcHelper>::new();
+    rl.set_helper(Some(CalcHelper));
+
+    let mut variables: HashMap<Variable, Complex> = HashMap::new();
+    // One long-lived Expression so `define()`d functions stick around
+    // between lines instead of being thrown away with each input.
+    let mut expr = Expression::new(String::new());
+
+    loop {
+        match rl.readline(">> ") {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                rl.add_history_entry(line);
+                expr.set_input(line.to_string());
+
+                if expr.is_definition() {
+                    match expr.define() {
+                        Ok(()) => println!("defined"),
+                        Err(e) => println!("error: {}", e),
+                    }
+                    continue;
+                }
+
+                if let Some((name, value)) = split_assignment(line) {
+                    if let Some(var) = parse_variable(name.trim()) {
+                        expr.set_input(value.trim().to_string());
+                        match expr.eval_with_var(&variables) {
+                            Some(result) => {
+                                variables.insert(var, result);
+                                println!("{} = {}", name.trim(), result);
+                            }
+                            None => println!("error: could not evaluate '{}'", value.trim()),
+                        }
+                        continue;
+                    }
+                }
+
+                expr.set_input(line.to_string());
+                match expr.eval_with_var(&variables) {
+                    Some(result) => println!("{}", result),
+                    None => println!("error: could not evaluate '{}'", line),
+                }
+            }
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                println!("error: {:?}", err);
+                break;
+            }
+        }
+    }
+}
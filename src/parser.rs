@@ -1,21 +1,37 @@
 // Math expression parser from infix to postfix
 
+use std::collections::HashMap;
+
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while1},
     character::complete::char,
     character::complete::space0 as space,
     combinator::{map, map_res, not, opt, peek},
-    error::ParseError,
-    multi::many0,
-    sequence::{delimited, pair, tuple},
+    multi::separated_list1,
+    sequence::{pair, tuple},
     IResult,
 };
 
-type Number = f64;
+use super::complex::Complex;
+use super::error::EvalError;
+
+type Number = Complex;
 
 use std::str::FromStr;
 
+// Comparison and logical operators report their result as 1.0/0.0 since
+// that's just as much "not a real boolean type" as Complex's own
+// real/imaginary split.
+fn to_flag(cond: bool) -> Number {
+    Complex::real(if cond { 1.0 } else { 0.0 })
+}
+
+// `&&`/`||` treat any non-zero complex value as true, same as C.
+fn is_truthy(value: Number) -> bool {
+    value.re != 0.0 || value.im != 0.0
+}
+
 trait Stack<T> {
     fn top(&self) -> Option<T>;
 }
@@ -29,12 +45,17 @@ impl<T: Clone> Stack<T> for Vec<T> {
         }
     }
 }
-#[derive(Debug, Copy, PartialEq, Clone)]
+// Not Copy: "<=", "&&" etc. need more than one char to display correctly.
+#[derive(Debug, PartialEq, Clone)]
 pub struct Operator {
-    symbol: char,
+    symbol: String,
+    // Byte offset of this operator in the original input, for
+    // `EvalError::MissingOperand`. `Parser::new` no longer strips
+    // whitespace, so this lines up with what the user actually typed.
+    pos: usize,
     precedence: u8,
     is_left_associative: bool,
-    operation: fn(f64, f64) -> f64,
+    operation: fn(Number, Number) -> Number,
 }
 
 impl Operator {
@@ -44,8 +65,11 @@ impl Operator {
         is_left_associative: bool,
         operation: fn(Number, Number) -> Number,
     ) -> Token {
+        // The real position is filled in by `Parser::with_pos` once the
+        // token's place in the input is known.
         Token::Operator(Operator {
-            symbol: symbol.to_string().chars().next().unwrap(),
+            symbol: symbol.to_string(),
+            pos: 0,
             precedence,
             is_left_associative,
             operation,
@@ -55,19 +79,60 @@ impl Operator {
     fn apply(&self, a: Number, b: Number) -> Number {
         (self.operation)(a, b)
     }
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     // Token can mean a number, word, operator
-    Number(f64),
+    Number(Number),
     Variable(Variable),
     Operator(Operator),
-    LeftParen,
-    RightParen,
+    // Byte offset of the paren, for `EvalError::MismatchedParen`.
+    LeftParen(usize),
+    RightParen(usize),
+    Comma,
     Negate,
+    // `cond ? a : b` - Question marks where the branches start, Colon is
+    // consumed entirely while shunting so only Question reaches the
+    // postfix stream. The byte offset is for `EvalError::MissingOperand`
+    // when a branch or the condition is missing, e.g. "1 < 2 ? 3".
+    Question(usize),
+    Colon,
+
+    // The byte offset is for `EvalError::MissingOperand` when the argument
+    // count below doesn't match the function's arity. The count is the
+    // number of comma-separated arguments `shunting_yard` actually saw
+    // between this call's parens - it's 0 at tokenize time and filled in
+    // there once the matching ')' is seen, since evaluation-stack depth
+    // alone can't tell a short call from one that stole an operand meant
+    // for an enclosing call.
+    Function(Function, usize, usize),
+    // A bare name, not one of the built-ins above - only meaningful as a
+    // call to a user-defined function, e.g. `square` in `square(3)`. The
+    // byte offset is for `EvalError::UnknownFunction`; the argument count
+    // is filled in the same way and for the same reason as `Function`'s.
+    Ident(String, usize, usize),
+}
+
+// A token's byte range in the original input, or `None` for a token with
+// no source text of its own (the synthetic '*' from
+// `Parser::insert_implicit_multiplication`).
+type Span = Option<(usize, usize)>;
 
-    Function(Function),
+// A user-defined function, e.g. `square(x) = x^2`: its parameters and its
+// body already reduced to postfix, ready for `Parser::calculate_with_env`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Definition {
+    pub params: Vec<Variable>,
+    pub body: Vec<Token>,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -75,6 +140,30 @@ pub enum Function {
     Sin,
     Cos,
     Tan,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    Sqrt,
+    Ln,
+    Log,
+    Exp,
+    Abs,
+    Floor,
+    Ceil,
+    Min,
+    Max,
+}
+
+impl Function {
+    // How many postfix operands this function consumes. `Log` is the
+    // `log(base, x)` form; `Ln` stays the unary natural log.
+    pub fn arity(&self) -> usize {
+        match self {
+            Function::Atan2 | Function::Log | Function::Min | Function::Max => 2,
+            _ => 1,
+        }
+    }
 }
 
 impl Token {
@@ -115,13 +204,30 @@ impl Stringify for Vec<Token> {
                     Variable::Z => "z".to_string(),
                 },
                 Token::Operator(op) => op.symbol.to_string(),
-                Token::LeftParen => "(".to_string(),
-                Token::RightParen => ")".to_string(),
+                Token::LeftParen(_) => "(".to_string(),
+                Token::RightParen(_) => ")".to_string(),
+                Token::Comma => ",".to_string(),
                 Token::Negate => "-".to_string(),
-                Token::Function(f) => match f {
+                Token::Question(_) => "?".to_string(),
+                Token::Colon => ":".to_string(),
+                Token::Ident(name, _, _) => name.clone(),
+                Token::Function(f, _, _) => match f {
                     Function::Sin => "sin".to_string(),
                     Function::Cos => "cos".to_string(),
                     Function::Tan => "tan".to_string(),
+                    Function::Asin => "asin".to_string(),
+                    Function::Acos => "acos".to_string(),
+                    Function::Atan => "atan".to_string(),
+                    Function::Atan2 => "atan2".to_string(),
+                    Function::Sqrt => "sqrt".to_string(),
+                    Function::Ln => "ln".to_string(),
+                    Function::Log => "log".to_string(),
+                    Function::Exp => "exp".to_string(),
+                    Function::Abs => "abs".to_string(),
+                    Function::Floor => "floor".to_string(),
+                    Function::Ceil => "ceil".to_string(),
+                    Function::Min => "min".to_string(),
+                    Function::Max => "max".to_string(),
                 },
             })
             .collect::<Vec<String>>()
@@ -139,7 +245,7 @@ impl Pop for Vec<Token> {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 
 pub enum Variable {
     // allow only x, y, z for now
@@ -152,6 +258,15 @@ pub struct Parser {
     input: String,
 }
 
+// Tracks a currently-open '(' while `shunting_yard` runs so it can tell how
+// many arguments a call actually received instead of trusting however deep
+// the evaluation stack happens to be.
+struct ParenFrame {
+    is_call: bool,
+    commas: usize,
+    has_content: bool,
+}
+
 impl Default for Parser {
     fn default() -> Self {
         Self {
@@ -163,20 +278,29 @@ impl Default for Parser {
 impl Parser {
     pub fn new(i: String) -> Self {
         Self {
-            input: i
-                .chars()
-                .filter(|c| !c.is_whitespace())
-                .map(|c| c.to_string())
-                .collect::<Vec<String>>()
-                .join(""),
+            input: i,
         }
     }
 
     pub fn parse_number(input: &str) -> IResult<&str, Token> {
-        // number could be a float or an integer
-        map(
-            take_while1(|c: char| c.is_digit(10) || c == '.'),
-            |s: &str| Token::Number(f64::from_str(s).unwrap()),
+        // number could be a float or an integer, optionally suffixed with
+        // 'i' to mark it as the imaginary part instead of the real one,
+        // e.g. "3i". `map_res` lets a malformed float (e.g. "1.2.3") fail
+        // the parse instead of panicking.
+        map_res(
+            pair(
+                take_while1(|c: char| c.is_digit(10) || c == '.'),
+                opt(char('i')),
+            ),
+            |(s, imaginary): (&str, Option<char>)| {
+                f64::from_str(s).map(|value| {
+                    Token::Number(if imaginary.is_some() {
+                        Complex::new(0.0, value)
+                    } else {
+                        Complex::real(value)
+                    })
+                })
+            },
         )(input)
     }
 
@@ -186,35 +310,137 @@ impl Parser {
             "x" => Token::Variable(Variable::X),
             "y" => Token::Variable(Variable::Y),
             "z" => Token::Variable(Variable::Z),
-            _ => panic!("Invalid variable"),
+            _ => unreachable!("alt() only matches x/y/z"),
         })(input)
     }
 
     pub fn parse_function(input: &str) -> IResult<&str, Token> {
-        // function could be sin, cos, tan
+        // "atan2" must be tried before "atan", longest tag first, or it'd
+        // split into Function(Atan) followed by a bare Number(2).
         map(
-            alt((tag("sin"), tag("cos"), tag("tan"))),
+            alt((
+                alt((
+                    tag("asin"),
+                    tag("acos"),
+                    tag("atan2"),
+                    tag("atan"),
+                    tag("sqrt"),
+                    tag("ln"),
+                    tag("log"),
+                    tag("exp"),
+                )),
+                alt((
+                    tag("abs"),
+                    tag("floor"),
+                    tag("ceil"),
+                    tag("min"),
+                    tag("max"),
+                    tag("sin"),
+                    tag("cos"),
+                    tag("tan"),
+                )),
+            )),
+            // The real position is filled in later by `Parser::with_pos`.
             |s: &str| match s {
-                "sin" => Token::Function(Function::Sin),
-                "cos" => Token::Function(Function::Cos),
-                "tan" => Token::Function(Function::Tan),
-                _ => panic!("Invalid function"),
+                "sin" => Token::Function(Function::Sin, 0, 0),
+                "cos" => Token::Function(Function::Cos, 0, 0),
+                "tan" => Token::Function(Function::Tan, 0, 0),
+                "asin" => Token::Function(Function::Asin, 0, 0),
+                "acos" => Token::Function(Function::Acos, 0, 0),
+                "atan" => Token::Function(Function::Atan, 0, 0),
+                "atan2" => Token::Function(Function::Atan2, 0, 0),
+                "sqrt" => Token::Function(Function::Sqrt, 0, 0),
+                "ln" => Token::Function(Function::Ln, 0, 0),
+                "log" => Token::Function(Function::Log, 0, 0),
+                "exp" => Token::Function(Function::Exp, 0, 0),
+                "abs" => Token::Function(Function::Abs, 0, 0),
+                "floor" => Token::Function(Function::Floor, 0, 0),
+                "ceil" => Token::Function(Function::Ceil, 0, 0),
+                "min" => Token::Function(Function::Min, 0, 0),
+                "max" => Token::Function(Function::Max, 0, 0),
+                _ => unreachable!("alt() only matches the tags listed above"),
             },
         )(input)
     }
 
+    pub fn parse_ident(input: &str) -> IResult<&str, Token> {
+        // anything else alphanumeric is a call to a user-defined function.
+        // The position is filled in later by `Parser::with_pos`.
+        map(take_while1(|c: char| c.is_alphanumeric()), |s: &str| {
+            Token::Ident(s.to_string(), 0, 0)
+        })(input)
+    }
+
+    // Detects `name(params) = body` and splits it into the definition's
+    // head and its still-unparsed body, e.g. "square(x)=x^2" -> body "x^2".
+    pub fn parse_definition(input: &str) -> IResult<&str, (String, Vec<Variable>)> {
+        let (input, name) = take_while1(|c: char| c.is_alphanumeric())(input)?;
+        let (input, _) = char('(')(input)?;
+        let (input, params) =
+            separated_list1(char(','), alt((tag("x"), tag("y"), tag("z"))))(input)?;
+        let (input, _) = char(')')(input)?;
+        let (input, _) = char('=')(input)?;
+
+        let params = params
+            .into_iter()
+            .map(|p| match p {
+                "x" => Variable::X,
+                "y" => Variable::Y,
+                "z" => Variable::Z,
+                _ => unreachable!(),
+            })
+            .collect();
+
+        Ok((input, (name.to_string(), params)))
+    }
+
+    // Tries to read `self.input` as a function definition, returning the
+    // name, its parameters, and the unparsed body on success.
+    pub fn try_definition(&self) -> Option<(String, Vec<Variable>, String)> {
+        match Self::parse_definition(self.input.as_str()) {
+            Ok((body, (name, params))) => Some((name, params, body.to_string())),
+            Err(_) => None,
+        }
+    }
+
+    pub fn parse_constant(input: &str) -> IResult<&str, Token> {
+        // pi and e are folded straight into numbers, not kept as symbols
+        map(alt((tag("pi"), tag("e"))), |s: &str| match s {
+            "pi" => Token::Number(Complex::real(std::f64::consts::PI)),
+            "e" => Token::Number(Complex::real(std::f64::consts::E)),
+            _ => unreachable!(),
+        })(input)
+    }
+
     fn negate(input: &str) -> IResult<&str, ()> {
         map(tuple((opt(tag(" ")), char('-'), opt(tag(" ")))), |_| ())(input)
     }
     fn parse_operator(input: &str) -> IResult<&str, Token> {
+        // Two-char tags ("<=", "==", "&&", ...) must come before their
+        // single-char prefixes ("<", ...) so they aren't split in two.
         let (input, symbol) = alt((
-            tag("+"),
-            tag("-"),
-            tag("*"),
-            tag("/"),
-            tag("^"),
-            tag("("),
-            tag(")"),
+            alt((
+                tag("<="),
+                tag(">="),
+                tag("=="),
+                tag("!="),
+                tag("&&"),
+                tag("||"),
+            )),
+            alt((
+                tag("+"),
+                tag("-"),
+                tag("*"),
+                tag("/"),
+                tag("^"),
+                tag("("),
+                tag(")"),
+                tag(","),
+                tag("<"),
+                tag(">"),
+                tag("?"),
+                tag(":"),
+            )),
         ))(input)?;
         let op = match symbol {
             "+" => Operator::new("+", 2, true, |a, b| a + b),
@@ -225,8 +451,26 @@ impl Parser {
             "*" => Operator::new("*", 3, true, |a, b| a * b),
             "/" => Operator::new("/", 3, true, |a, b| a / b),
             "^" => Operator::new("^", 4, false, |a, b| a.powf(b)),
-            "(" => Token::LeftParen,
-            ")" => Token::RightParen,
+            // The real position is filled in later by `Parser::with_pos`.
+            "(" => Token::LeftParen(0),
+            ")" => Token::RightParen(0),
+            "," => Token::Comma,
+            "?" => Token::Question(0),
+            ":" => Token::Colon,
+            // Ordering only really means anything for the real part, so
+            // these compare `.re` and ignore any imaginary component.
+            "<" => Operator::new("<", 1, true, |a, b| to_flag(a.re < b.re)),
+            ">" => Operator::new(">", 1, true, |a, b| to_flag(a.re > b.re)),
+            "<=" => Operator::new("<=", 1, true, |a, b| to_flag(a.re <= b.re)),
+            ">=" => Operator::new(">=", 1, true, |a, b| to_flag(a.re >= b.re)),
+            "==" => Operator::new("==", 1, true, |a, b| to_flag(a == b)),
+            "!=" => Operator::new("!=", 1, true, |a, b| to_flag(a != b)),
+            "&&" => Operator::new("&&", 0, true, |a, b| {
+                to_flag(is_truthy(a) && is_truthy(b))
+            }),
+            "||" => Operator::new("||", 0, true, |a, b| {
+                to_flag(is_truthy(a) || is_truthy(b))
+            }),
             _ => unreachable!(),
         };
         Ok((input, op))
@@ -236,42 +480,161 @@ impl Parser {
         // parser to match all tokens, including space and the operators
         alt((
             Self::parse_number,
+            Self::parse_function,
+            Self::parse_constant,
             Self::parse_variable,
             Self::parse_operator,
-            Self::parse_function,
+            Self::parse_ident,
         ))(input)
     }
 
-    pub fn tokens(&self) -> IResult<&str, Vec<Token>> {
-        // parse a string into tokens
-        many0(Self::token)(self.input.as_str())
+    // Parses the whole input into tokens. `Parser::new` keeps whitespace
+    // intact precisely so the byte offsets computed here line up with what
+    // the user actually typed.
+    pub fn tokens(&self) -> Result<Vec<Token>, EvalError> {
+        Ok(self
+            .tokens_with_spans()?
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect())
+    }
+
+    // Like `tokens`, but also returns each token's byte span in the
+    // original input - `None` for the synthetic '*' that
+    // `insert_implicit_multiplication` splices in, since it has no text of
+    // its own to point at. `Highlighter::highlight` uses the spans to
+    // splice color codes around the user's original text instead of
+    // reconstructing it from the token stream.
+    pub fn tokens_with_spans(&self) -> Result<Vec<(Token, Span)>, EvalError> {
+        let mut result = Vec::new();
+        let mut remaining = self.input.as_str();
+
+        loop {
+            // space0 matches zero or more, so it can never fail. The error
+            // type is never inspected, but `unwrap()` still needs it
+            // pinned down to something concrete.
+            let (after_space, _) =
+                space::<&str, nom::error::Error<&str>>(remaining).unwrap();
+            if after_space.is_empty() {
+                break;
+            }
+
+            let pos = self.input.len() - after_space.len();
+            match Self::token(after_space) {
+                Ok((rest, token)) => {
+                    let end = pos + (after_space.len() - rest.len());
+                    result.push((Self::with_pos(token, pos), Some((pos, end))));
+                    remaining = rest;
+                }
+                Err(_) => return Err(EvalError::UnexpectedToken { pos }),
+            }
+        }
+
+        Ok(Self::insert_implicit_multiplication(result))
+    }
+
+    // The individual `parse_*` combinators only ever see a suffix of the
+    // input, so they can't know their own byte offset - `tokens` fills it
+    // in here once it knows where each token actually starts.
+    fn with_pos(token: Token, pos: usize) -> Token {
+        match token {
+            Token::LeftParen(_) => Token::LeftParen(pos),
+            Token::RightParen(_) => Token::RightParen(pos),
+            Token::Ident(name, _, args) => Token::Ident(name, pos, args),
+            Token::Function(f, _, args) => Token::Function(f, pos, args),
+            Token::Question(_) => Token::Question(pos),
+            Token::Operator(op) => Token::Operator(Operator { pos, ..op }),
+            other => other,
+        }
+    }
+
+    // "5x" and "2(x + 1)" carry no explicit operator between the operands,
+    // so splice a '*' in wherever two operand-like tokens sit back to back.
+    // It has no position of its own in the source, so it reports 0 - it
+    // can never be short an operand, so it can never surface in an error.
+    fn insert_implicit_multiplication(tokens: Vec<(Token, Span)>) -> Vec<(Token, Span)> {
+        let mut result: Vec<(Token, Span)> = Vec::with_capacity(tokens.len());
+
+        for (token, span) in tokens {
+            if let Some((prev, _)) = result.last() {
+                let prev_is_operand_end = matches!(
+                    prev,
+                    Token::Number(_) | Token::Variable(_) | Token::RightParen(_)
+                );
+                let starts_operand = matches!(
+                    token,
+                    Token::Number(_)
+                        | Token::Variable(_)
+                        | Token::Function(_, _, _)
+                        | Token::Ident(_, _, _)
+                        | Token::LeftParen(_)
+                );
+
+                if prev_is_operand_end && starts_operand {
+                    result.push((Operator::new("*", 3, true, |a, b| a * b), None));
+                }
+            }
+            result.push((token, span));
+        }
+
+        result
     }
 
-    fn tilt_until(operators: &mut Vec<Token>, output: &mut Vec<Token>, stop: Token) -> bool {
+    // Pops operators into `output` until (and including) the next '(',
+    // returning its position, or `None` if the stack runs out first.
+    fn tilt_until_left_paren(operators: &mut Vec<Token>, output: &mut Vec<Token>) -> Option<usize> {
         while let Some(token) = operators.pop() {
-            if token == stop {
-                return true;
+            if let Token::LeftParen(pos) = token {
+                return Some(pos);
             }
             output.push(token)
         }
-        false
+        None
     }
 
-    pub fn shunting_yard(tokens: Vec<Token>) -> Result<Vec<Token>, String> {
+    pub fn shunting_yard(tokens: Vec<Token>) -> Result<Vec<Token>, EvalError> {
         let mut output: Vec<Token> = Vec::new();
         let mut operators: Vec<Token> = Vec::new();
+        // One frame per currently-open '(', pushed and popped in lockstep
+        // with `operators`. Tracks how many comma-separated arguments a
+        // call has actually seen, so `RightParen` can stamp the real count
+        // onto its Function/Ident token instead of leaving
+        // `calculate_with_env` to infer it from shared stack depth.
+        let mut parens: Vec<ParenFrame> = Vec::new();
 
         for token in tokens {
+            // A comma or ')' doesn't itself count as an argument's
+            // content - everything else occurring while a call's parens
+            // are open does (a nested call or group included, since that
+            // whole subexpression is one argument).
+            if !matches!(token, Token::Comma | Token::RightParen(_)) {
+                if let Some(frame) = parens.last_mut() {
+                    frame.has_content = true;
+                }
+            }
+
             match token {
                 Token::Number(_) => output.push(token),
-                Token::LeftParen => operators.push(token),
-                Token::Function(_) => operators.push(token),
+                Token::LeftParen(_) => {
+                    let is_call = matches!(
+                        operators.top(),
+                        Some(Token::Function(_, _, _)) | Some(Token::Ident(_, _, _))
+                    );
+                    parens.push(ParenFrame {
+                        is_call,
+                        commas: 0,
+                        has_content: false,
+                    });
+                    operators.push(token);
+                }
+                Token::Function(_, _, _) => operators.push(token),
+                Token::Ident(_, _, _) => operators.push(token),
                 Token::Variable(_) => output.push(token),
                 Token::Negate => operators.push(token),
-                Token::Operator(operator) => {
+                Token::Operator(ref operator) => {
                     while let Some(top) = operators.top() {
                         match top {
-                            Token::LeftParen => break,
+                            Token::LeftParen(_) => break,
                             Token::Operator(top_op) => {
                                 let p = top_op.precedence;
                                 let q = operator.precedence;
@@ -284,59 +647,181 @@ impl Parser {
                                     break;
                                 }
                             }
-                            Token::Number(_) => todo!(),
-                            Token::Variable(_) => todo!(),
-                            Token::RightParen => {
-                                while (top != Token::LeftParen) {
-                                    assert!(operators.pop().is_some());
-                                }
+                            // Never pushed onto `operators` - they go
+                            // straight to `output` above.
+                            Token::Number(_) | Token::Variable(_) => {
+                                unreachable!("operands never sit on the operator stack")
                             }
-                            Token::Negate => todo!(),
-                            Token::Function(_) => todo!(),
+                            // Never pushed onto `operators` either - both
+                            // are fully consumed by their own arms below.
+                            Token::RightParen(_) | Token::Comma | Token::Colon => {
+                                unreachable!("consumed by their own arm, never pushed")
+                            }
+                            // A unary minus, a function name, or a bare
+                            // call binds tighter than any following binary
+                            // operator could reorder it past, so treat it
+                            // like '(': a boundary nothing pops through.
+                            Token::Negate | Token::Function(_, _, _) | Token::Ident(_, _, _) => {
+                                break
+                            }
+                            // A pending ternary is a boundary, same as '(':
+                            // nothing pops past it until its ':' is seen.
+                            Token::Question(_) => break,
+                        }
+                    }
+                    operators.push(token);
+                }
+                Token::RightParen(pos) => {
+                    if Self::tilt_until_left_paren(&mut operators, &mut output).is_none() {
+                        return Err(EvalError::MismatchedParen { pos });
+                    }
+                    let frame = parens.pop().expect("pushed in lockstep with '('");
+                    // A function (or user-defined call) name sits just
+                    // under the matching '(' - flush it now so it lands
+                    // right after its arguments, stamped with how many
+                    // arguments it actually got.
+                    if frame.is_call {
+                        let arg_count = if frame.has_content {
+                            frame.commas + 1
+                        } else {
+                            0
+                        };
+                        let name = operators
+                            .pop()
+                            .expect("is_call means a Function/Ident sits right under this '('");
+                        output.push(match name {
+                            Token::Function(f, pos, _) => Token::Function(f, pos, arg_count),
+                            Token::Ident(n, pos, _) => Token::Ident(n, pos, arg_count),
+                            other => other,
+                        });
+                    }
+                }
+                Token::Comma => {
+                    if let Some(frame) = parens.last_mut() {
+                        frame.commas += 1;
+                    }
+                    // A comma separates function arguments: flush the
+                    // current argument's operators but leave the '(' so
+                    // later commas/the closing ')' still find it.
+                    while let Some(top) = operators.top() {
+                        if matches!(top, Token::LeftParen(_)) {
+                            break;
+                        }
+                        output.push(operators.pop().unwrap());
+                    }
+                }
+                Token::Question(_) => {
+                    // '?' binds loosest of all: flush the whole condition
+                    // expression, then mark where the branches begin.
+                    while let Some(top) = operators.top() {
+                        if matches!(top, Token::LeftParen(_)) {
+                            break;
                         }
+                        output.push(operators.pop().unwrap());
                     }
                     operators.push(token);
                 }
-                Token::RightParen => {
-                    if !Self::tilt_until(&mut operators, &mut output, Token::LeftParen) {
-                        return Err(String::from("Mismatched ')'"));
+                Token::Colon => {
+                    // Flush the true-branch but leave the matching '?' in
+                    // place so the false-branch and final cleanup find it.
+                    while let Some(top) = operators.top() {
+                        if matches!(top, Token::Question(_)) {
+                            break;
+                        }
+                        output.push(operators.pop().unwrap());
                     }
                 }
             }
         }
 
-        if Self::tilt_until(&mut operators, &mut output, Token::LeftParen) {
-            return Err(String::from("Mismatched '('"));
+        if let Some(pos) = Self::tilt_until_left_paren(&mut operators, &mut output) {
+            return Err(EvalError::MismatchedParen { pos });
         }
 
         assert!(operators.is_empty());
         Ok(output)
     }
 
-    pub fn calculate(postfix_tokens: Vec<Token>) -> Result<Number, String> {
+    // Expressions without variables or user-defined functions never need
+    // an environment or a definitions map, so this is calculate_with_env
+    // with nothing bound.
+    pub fn calculate(postfix_tokens: Vec<Token>) -> Result<Number, EvalError> {
+        Self::calculate_with_env(postfix_tokens, &HashMap::new(), &HashMap::new())
+    }
+
+    pub fn calculate_with_env(
+        postfix_tokens: Vec<Token>,
+        env: &HashMap<Variable, Number>,
+        defs: &HashMap<String, Definition>,
+    ) -> Result<Number, EvalError> {
+        Self::calculate_with_depth(postfix_tokens, env, defs, 0)
+    }
+
+    // A user-defined function's body can itself call user-defined
+    // functions, so evaluating one recurses back into this function. With
+    // no base case that recursion is unbounded and would otherwise blow the
+    // native call stack, aborting the whole process instead of returning an
+    // `EvalError` - so every recursive step through `Token::Ident` counts
+    // against this limit.
+    const MAX_RECURSION_DEPTH: usize = 256;
+
+    fn calculate_with_depth(
+        postfix_tokens: Vec<Token>,
+        env: &HashMap<Variable, Number>,
+        defs: &HashMap<String, Definition>,
+        depth: usize,
+    ) -> Result<Number, EvalError> {
         let mut stack = Vec::new();
 
         for token in postfix_tokens {
             match token {
                 Token::Number(number) => stack.push(number),
-                Token::Function(_) => match token {
-                    Token::Function(Function::Sin) => {
-                        if let Some(x) = stack.pop() {
-                            stack.push(x.sin());
-                        }
+                Token::Function(function, pos, arg_count) => {
+                    let arity = function.arity();
+                    if arg_count != arity {
+                        return Err(EvalError::MissingOperand {
+                            op: format!("{:?}", function),
+                            pos,
+                        });
                     }
-                    Token::Function(Function::Cos) => {
-                        if let Some(x) = stack.pop() {
-                            stack.push(x.cos());
+                    let args = stack.split_off(stack.len() - arity);
+                    let result = match function {
+                        Function::Sin => args[0].sin(),
+                        Function::Cos => args[0].cos(),
+                        Function::Tan => args[0].tan(),
+                        Function::Asin => args[0].asin(),
+                        Function::Acos => args[0].acos(),
+                        Function::Atan => args[0].atan(),
+                        // atan2 has no single-valued complex generalization
+                        // worth the complexity here, so it works on the
+                        // real parts like a plain calculator.
+                        Function::Atan2 => Complex::real(args[0].re.atan2(args[1].re)),
+                        Function::Sqrt => args[0].sqrt(),
+                        Function::Ln => args[0].ln(),
+                        Function::Log => args[1].ln() / args[0].ln(),
+                        Function::Exp => args[0].exp(),
+                        // The magnitude of a complex number is real by
+                        // definition.
+                        Function::Abs => Complex::real(args[0].abs()),
+                        Function::Floor => Complex::new(args[0].re.floor(), args[0].im.floor()),
+                        Function::Ceil => Complex::new(args[0].re.ceil(), args[0].im.ceil()),
+                        Function::Min => {
+                            if args[0].re <= args[1].re {
+                                args[0]
+                            } else {
+                                args[1]
+                            }
                         }
-                    }
-                    Token::Function(Function::Tan) => {
-                        if let Some(x) = stack.pop() {
-                            stack.push(x.tan());
+                        Function::Max => {
+                            if args[0].re >= args[1].re {
+                                args[0]
+                            } else {
+                                args[1]
+                            }
                         }
-                    }
-                    _ => unreachable!("Unexpected function {:?} during calculation", token),
-                },
+                    };
+                    stack.push(result);
+                }
                 Token::Operator(operator) => {
                     if let Some(y) = stack.pop() {
                         if let Some(x) = stack.pop() {
@@ -344,10 +829,10 @@ impl Parser {
                             continue;
                         }
                     }
-                    return Err(format!(
-                        "Missing operand for operator '{}'",
-                        operator.symbol
-                    ));
+                    return Err(EvalError::MissingOperand {
+                        op: operator.symbol().to_string(),
+                        pos: operator.pos(),
+                    });
                 }
 
                 Token::Negate => {
@@ -356,30 +841,71 @@ impl Parser {
                     }
                 }
 
-                Token::Variable(_) => match token {
-                    Token::Variable(Variable::X) => {
-                        if let Some(x) = stack.pop() {
-                            stack.push(x);
-                        }
+                Token::Variable(variable) => {
+                    let value = env.get(&variable).ok_or_else(|| EvalError::UnboundVariable {
+                        name: format!("{:?}", variable),
+                    })?;
+                    stack.push(*value);
+                }
+
+                Token::Ident(name, pos, arg_count) => {
+                    if depth >= Self::MAX_RECURSION_DEPTH {
+                        return Err(EvalError::RecursionLimit { pos });
                     }
-                    Token::Variable(Variable::Y) => {
-                        if let Some(y) = stack.pop() {
-                            stack.push(y);
-                        }
+                    let def = defs
+                        .get(&name)
+                        .ok_or(EvalError::UnknownFunction { name: name.clone(), pos })?;
+                    let arity = def.params.len();
+                    if arg_count != arity {
+                        return Err(EvalError::MissingOperand { op: name, pos });
                     }
-                    Token::Variable(Variable::Z) => {
-                        if let Some(z) = stack.pop() {
-                            stack.push(z);
+                    let args = stack.split_off(stack.len() - arity);
+
+                    let mut call_env = HashMap::new();
+                    for (param, value) in def.params.iter().zip(args.iter()) {
+                        call_env.insert(*param, *value);
+                    }
+
+                    stack.push(Self::calculate_with_depth(
+                        def.body.clone(),
+                        &call_env,
+                        defs,
+                        depth + 1,
+                    )?);
+                }
+
+                Token::Question(pos) => {
+                    if let Some(false_val) = stack.pop() {
+                        if let Some(true_val) = stack.pop() {
+                            if let Some(cond) = stack.pop() {
+                                stack.push(if cond != 0.0 { true_val } else { false_val });
+                                continue;
+                            }
                         }
                     }
-                    _ => unreachable!("Unexpected variable {:?} during calculation", token),
-                },
+                    return Err(EvalError::MissingOperand {
+                        op: "?:".to_string(),
+                        pos,
+                    });
+                }
                 _ => unreachable!("Unexpected token {:?} during calculation", token),
             }
         }
 
-        if stack.len() != 1 {
-            return Err(format!("Expected 1 value on stack, found {}", stack.len()));
+        if stack.is_empty() {
+            // The whole expression reduced to nothing - there's no single
+            // offending character to point at, so `pos` is a placeholder,
+            // not a real location.
+            return Err(EvalError::MissingOperand {
+                op: "expression".to_string(),
+                pos: 0,
+            });
+        }
+        if stack.len() > 1 {
+            // More than one value left over means the input had extra,
+            // disconnected operands (e.g. "1, 2" outside a function call)
+            // rather than a missing one.
+            return Err(EvalError::UnexpectedToken { pos: 0 });
         }
         Ok(stack.pop().unwrap())
     }
@@ -388,7 +914,10 @@ impl Parser {
 #[cfg(test)]
 
 mod test_parser {
-    use super::{Function, Operator, Token};
+    use std::collections::HashMap;
+
+    use super::{Definition, EvalError, Function, Token};
+    use crate::complex::Complex;
     use crate::parser::{Parser, Variable};
 
     // evals
@@ -396,21 +925,21 @@ mod test_parser {
     fn test_float() {
         let mut p = Parser::new("1.2".to_string());
         let result = p.tokens();
-        assert_eq!(result.unwrap().1, vec![Token::Number(1.2)]);
+        assert_eq!(result.unwrap(), vec![Token::Number(Complex::real(1.2))]);
     }
 
     #[test]
     fn test_int() {
         let mut p = Parser::new("1".to_string());
         let result = p.tokens();
-        assert_eq!(result.unwrap().1, vec![Token::Number(1.0)]);
+        assert_eq!(result.unwrap(), vec![Token::Number(Complex::real(1.0))]);
     }
 
     #[test]
     fn test_variable() {
         let mut p = Parser::new("x".to_string());
         let result = p.tokens();
-        assert_eq!(result.unwrap().1, vec![Token::Variable(Variable::X)]);
+        assert_eq!(result.unwrap(), vec![Token::Variable(Variable::X)]);
     }
 
     #[test]
@@ -418,12 +947,12 @@ mod test_parser {
         let mut p = Parser::new("sin(x)".to_string());
         let result = p.tokens();
         assert_eq!(
-            result.unwrap().1,
+            result.unwrap(),
             vec![
-                Token::Function(Function::Sin),
-                Token::LeftParen,
+                Token::Function(Function::Sin, 0, 0),
+                Token::LeftParen(3),
                 Token::Variable(Variable::X),
-                Token::RightParen
+                Token::RightParen(5),
             ]
         );
     }
@@ -432,7 +961,7 @@ mod test_parser {
     fn print_shunting_yard() {
         let mut p = Parser::new("1 + 2 * 3".to_string());
         let result = p.tokens();
-        let result = Parser::shunting_yard(result.unwrap().1);
+        let result = Parser::shunting_yard(result.unwrap());
         println!("{:?}", result);
     }
 
@@ -440,7 +969,7 @@ mod test_parser {
     fn test_calculate_4_plus_2_times_3() {
         let mut p = Parser::new("4 + 2 * 3".to_string());
         let result = p.tokens();
-        let result = Parser::shunting_yard(result.unwrap().1);
+        let result = Parser::shunting_yard(result.unwrap());
         let result = Parser::calculate(result.unwrap());
         assert_eq!(result.unwrap(), 10.0);
     }
@@ -449,7 +978,7 @@ mod test_parser {
     fn test_calculate_4_dot_5_plus_2_times_3() {
         let mut p = Parser::new("4.5 + 2 * 3".to_string());
         let result = p.tokens();
-        let result = Parser::shunting_yard(result.unwrap().1);
+        let result = Parser::shunting_yard(result.unwrap());
         let result = Parser::calculate(result.unwrap());
         assert_eq!(result.unwrap(), 10.5);
     }
@@ -458,7 +987,7 @@ mod test_parser {
     fn test_calculate_4_leftparen_2_plus_3_rightparen() {
         let mut p = Parser::new("4 * (2 + 3)".to_string());
         let result = p.tokens();
-        let result = Parser::shunting_yard(result.unwrap().1);
+        let result = Parser::shunting_yard(result.unwrap());
         let result = Parser::calculate(result.unwrap());
         assert_eq!(result.unwrap(), 20.0);
     }
@@ -467,9 +996,242 @@ mod test_parser {
     fn test_negative_number() {
         let mut p = Parser::new("-1".to_string());
         let result = p.tokens();
-        let result = Parser::shunting_yard(result.unwrap().1);
+        let result = Parser::shunting_yard(result.unwrap());
         println!("{:?}", result);
         let result = Parser::calculate(result.unwrap());
         assert_eq!(result.unwrap(), -1.0);
     }
+
+    #[test]
+    fn test_calculate_with_env_binds_variable() {
+        let mut p = Parser::new("5x".to_string());
+        let result = p.tokens();
+        let result = Parser::shunting_yard(result.unwrap());
+        let mut env = HashMap::new();
+        env.insert(Variable::X, Complex::real(2.0));
+        let result = Parser::calculate_with_env(result.unwrap(), &env, &HashMap::new());
+        assert_eq!(result.unwrap(), 10.0);
+    }
+
+    #[test]
+    fn test_calculate_with_env_unbound_variable_errors() {
+        let mut p = Parser::new("x".to_string());
+        let result = p.tokens();
+        let result = Parser::shunting_yard(result.unwrap());
+        let result = Parser::calculate_with_env(result.unwrap(), &HashMap::new(), &HashMap::new());
+        assert_eq!(result, Err(EvalError::UnboundVariable { name: "X".to_string() }));
+    }
+
+    #[test]
+    fn test_sqrt() {
+        let mut p = Parser::new("sqrt(9)".to_string());
+        let result = p.tokens();
+        let result = Parser::shunting_yard(result.unwrap());
+        let result = Parser::calculate(result.unwrap());
+        assert_eq!(result.unwrap(), 3.0);
+    }
+
+    #[test]
+    fn test_two_argument_function() {
+        let mut p = Parser::new("min(3,1)".to_string());
+        let result = p.tokens();
+        let result = Parser::shunting_yard(result.unwrap());
+        let result = Parser::calculate(result.unwrap());
+        assert_eq!(result.unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_under_supplied_call_does_not_steal_outer_argument() {
+        // `min(3)` is missing its second argument, so it must not quietly
+        // steal the `9` that belongs to the enclosing `max` call.
+        let mut p = Parser::new("max(1, 9, min(3))".to_string());
+        let result = p.tokens();
+        let result = Parser::shunting_yard(result.unwrap());
+        let result = Parser::calculate(result.unwrap());
+        assert!(matches!(result, Err(EvalError::MissingOperand { .. })));
+    }
+
+    #[test]
+    fn test_function_call_followed_by_operator() {
+        let mut p = Parser::new("max(1,2) + 3".to_string());
+        let result = p.tokens();
+        let result = Parser::shunting_yard(result.unwrap());
+        let result = Parser::calculate(result.unwrap());
+        assert_eq!(result.unwrap(), 5.0);
+    }
+
+    #[test]
+    fn test_pi_constant() {
+        let mut p = Parser::new("pi".to_string());
+        let result = p.tokens();
+        assert_eq!(
+            result.unwrap(),
+            vec![Token::Number(Complex::real(std::f64::consts::PI))]
+        );
+    }
+
+    #[test]
+    fn test_parse_definition() {
+        let p = Parser::new("square(x)=x^2".to_string());
+        let (body, (name, params)) = Parser::parse_definition(p.input.as_str()).unwrap();
+        assert_eq!(name, "square");
+        assert_eq!(params, vec![Variable::X]);
+        assert_eq!(body, "x^2");
+    }
+
+    #[test]
+    fn test_calculate_user_defined_function() {
+        let p = Parser::new("x^2".to_string());
+        let body = Parser::shunting_yard(p.tokens().unwrap()).unwrap();
+
+        let mut defs = HashMap::new();
+        defs.insert(
+            "square".to_string(),
+            Definition {
+                params: vec![Variable::X],
+                body,
+            },
+        );
+
+        let call = Parser::new("square(3)".to_string());
+        let call = Parser::shunting_yard(call.tokens().unwrap()).unwrap();
+        let result = Parser::calculate_with_env(call, &HashMap::new(), &defs);
+        assert_eq!(result.unwrap(), 9.0);
+    }
+
+    #[test]
+    fn test_user_defined_function_without_base_case_hits_recursion_limit() {
+        // `f(x) = f(x - 1)` never stops calling itself, so this must come
+        // back as an error instead of overflowing the native call stack.
+        let body = Parser::shunting_yard(Parser::new("f(x-1)".to_string()).tokens().unwrap())
+            .unwrap();
+
+        let mut defs = HashMap::new();
+        defs.insert(
+            "f".to_string(),
+            Definition {
+                params: vec![Variable::X],
+                body,
+            },
+        );
+
+        let call = Parser::shunting_yard(Parser::new("f(0)".to_string()).tokens().unwrap())
+            .unwrap();
+        let result = Parser::calculate_with_env(call, &HashMap::new(), &defs);
+        assert!(matches!(result, Err(EvalError::RecursionLimit { .. })));
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        let p = Parser::new("1 < 2".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        assert_eq!(Parser::calculate(result.unwrap()).unwrap(), 1.0);
+
+        let p = Parser::new("2 >= 3".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        assert_eq!(Parser::calculate(result.unwrap()).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_comparison_below_addition() {
+        // "1 + 1 == 2" should parse as "(1 + 1) == 2", not "1 + (1 == 2)".
+        let p = Parser::new("1 + 1 == 2".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        assert_eq!(Parser::calculate(result.unwrap()).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let p = Parser::new("1 && 0".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        assert_eq!(Parser::calculate(result.unwrap()).unwrap(), 0.0);
+
+        let p = Parser::new("1 || 0".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        assert_eq!(Parser::calculate(result.unwrap()).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_ternary_true_branch() {
+        let p = Parser::new("1 < 2 ? 10 : 20".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        assert_eq!(Parser::calculate(result.unwrap()).unwrap(), 10.0);
+    }
+
+    #[test]
+    fn test_ternary_false_branch() {
+        let p = Parser::new("1 > 2 ? 10 : 20".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        assert_eq!(Parser::calculate(result.unwrap()).unwrap(), 20.0);
+    }
+
+    #[test]
+    fn test_ternary_with_variable_and_negate() {
+        let p = Parser::new("x > 0 ? x : -x".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        let mut env = HashMap::new();
+        env.insert(Variable::X, Complex::real(-5.0));
+        let result = Parser::calculate_with_env(result.unwrap(), &env, &HashMap::new());
+        assert_eq!(result.unwrap(), 5.0);
+    }
+
+    #[test]
+    fn test_imaginary_literal() {
+        let mut p = Parser::new("3i".to_string());
+        let result = p.tokens();
+        assert_eq!(result.unwrap(), vec![Token::Number(Complex::new(0.0, 3.0))]);
+    }
+
+    #[test]
+    fn test_sqrt_of_negative_number() {
+        let mut p = Parser::new("sqrt(-1)".to_string());
+        let result = p.tokens();
+        let result = Parser::shunting_yard(result.unwrap());
+        let result = Parser::calculate(result.unwrap()).unwrap();
+        assert_eq!(result.re.round(), 0.0);
+        assert_eq!(result.im.round(), 1.0);
+    }
+
+    #[test]
+    fn test_complex_addition() {
+        let mut p = Parser::new("(2 + 3i) + (1 + -1i)".to_string());
+        let result = p.tokens();
+        let result = Parser::shunting_yard(result.unwrap());
+        let result = Parser::calculate(result.unwrap()).unwrap();
+        assert_eq!(result, Complex::new(3.0, 2.0));
+    }
+
+    #[test]
+    fn test_unexpected_token_reports_position() {
+        let p = Parser::new("1 + @".to_string());
+        assert_eq!(p.tokens(), Err(EvalError::UnexpectedToken { pos: 4 }));
+    }
+
+    #[test]
+    fn test_mismatched_right_paren_reports_position() {
+        let p = Parser::new("(1 + 2))".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        assert_eq!(result, Err(EvalError::MismatchedParen { pos: 7 }));
+    }
+
+    #[test]
+    fn test_mismatched_left_paren_reports_position() {
+        let p = Parser::new("(1 + 2".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        assert_eq!(result, Err(EvalError::MismatchedParen { pos: 0 }));
+    }
+
+    #[test]
+    fn test_unknown_function_reports_name_and_position() {
+        let p = Parser::new("wibble(1)".to_string());
+        let result = Parser::shunting_yard(p.tokens().unwrap());
+        let result = Parser::calculate(result.unwrap());
+        assert_eq!(
+            result,
+            Err(EvalError::UnknownFunction {
+                name: "wibble".to_string(),
+                pos: 0,
+            })
+        );
+    }
 }